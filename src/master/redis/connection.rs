@@ -15,7 +15,15 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::prelude::*;
 use redis::Value;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
 
 use crate::errors::*;
 use crate::master::messages::{AddSite, AddVisitor};
@@ -25,77 +33,178 @@ use crate::redis::Redis;
 use crate::redis::RedisConfig;
 use crate::redis::RedisConnection;
 
-/// Redis instance with mCaptcha Redis module loaded
-pub struct MCaptchaRedis(Redis);
+/// Pool of Redis instances, each with the mCaptcha Redis module loaded.
+/// Holds one entry per shard node, letting load be split across several
+/// Redis instances instead of a single connection. This is client-side
+/// sharding (hash of the captcha key mod node count), not Redis Cluster
+/// protocol support: there's no replication, so a dead node still makes
+/// every captcha hashed to it error out rather than failing over
+pub struct MCaptchaRedis(Vec<Redis>);
 
-/// Connection to Redis instance with mCaptcha Redis module loaded
-pub struct MCaptchaRedisConnection(RedisConnection);
+/// Pool of connections to the Redis instance(s) with mCaptcha Redis
+/// module loaded. Module commands are routed to the shard owning the
+/// captcha key, the same way [MCaptchaRedis] groups its nodes
+#[derive(Clone)]
+pub struct MCaptchaRedisConnection(Vec<RedisConnection>);
 
 const GET: &str = "MCAPTCHA_CACHE.GET";
 const ADD_VISITOR: &str = "MCAPTCHA_CACHE.ADD_VISITOR";
 const DEL: &str = "MCAPTCHA_CACHE.DELETE_CAPTCHA";
 const ADD_CAPTCHA: &str = "MCAPTCHA_CACHE.ADD_CAPTCHA";
 const CAPTCHA_EXISTS: &str = "MCAPTCHA_CACHE.CAPTCHA_EXISTS";
+const ADD_CHALLENGE: &str = "MCAPTCHA_CACHE.ADD_CHALLENGE";
+const GET_CHALLENGE: &str = "MCAPTCHA_CACHE.GET_CHALLENGE";
+const DELETE_CHALLENGE: &str = "MCAPTCHA_CACHE.DELETE_CHALLENGE";
+const GET_DIFFICULTY: &str = "MCAPTCHA_CACHE.GET_DIFFICULTY";
 
 const MODULE_NAME: &str = "mcaptcha_cahce";
 
+/// The module-computed difficulty factor and active defense level for a
+/// captcha, read straight from the master so that clients issuing PoW
+/// challenges don't have to reconstruct [Defense](crate::master::Defense)
+/// state from the raw visitor count themselves
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Difficulty {
+    pub difficulty: u32,
+    pub defense_level: usize,
+}
+
+/// A PoW challenge issued to a client, keyed under its captcha and stored
+/// with a server-side TTL equal to `duration` so that it can be verified
+/// from any node sharing this Redis instance
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddChallenge {
+    pub difficulty: u32,
+    pub duration: u64,
+    pub challenge: String,
+}
+
 impl MCaptchaRedis {
     /// Get new [MCaptchaRedis]. Use this when executing commands that are
-    /// only supported by mCaptcha Redis module. Internally, when object
-    /// is created, checks are performed to check if the module is loaded and if
-    /// the required commands are available
+    /// only supported by mCaptcha Redis module. Accepts either a single
+    /// endpoint or a [RedisConfig::Sharded] list of endpoints, in which
+    /// case one multiplexed connection per node is kept (there is no pool
+    /// of several connections to a node) and module commands are
+    /// routed client-side to the shard that owns the affected captcha
+    /// (this is static hash sharding, not Redis Cluster protocol
+    /// support). Internally, when object is created, `is_module_loaded`
+    /// is run against every node so that a partial module deployment is
+    /// caught at startup
     pub async fn new(redis: RedisConfig) -> CaptchaResult<Self> {
-        let redis = Redis::new(redis).await?;
-        let m = MCaptchaRedis(redis);
+        let nodes = match redis {
+            RedisConfig::Single(url) => vec![Redis::new(RedisConfig::Single(url)).await?],
+            RedisConfig::Sharded(urls) => {
+                if urls.is_empty() {
+                    return Err(CaptchaError::MCaptchaRedisModuleError);
+                }
+
+                let mut nodes = Vec::with_capacity(urls.len());
+                for url in urls.into_iter() {
+                    nodes.push(Redis::new(RedisConfig::Single(url)).await?);
+                }
+                nodes
+            }
+        };
+
+        let m = MCaptchaRedis(nodes);
         m.get_client().is_module_loaded().await?;
         Ok(m)
     }
 
-    /// Get connection to a Redis instance with mCaptcha Redis module loaded
+    /// Get a pool of connections to every Redis node with mCaptcha Redis
+    /// module loaded
     ///
     /// Uses interior mutability so look out for panics!
     pub fn get_client(&self) -> MCaptchaRedisConnection {
-        MCaptchaRedisConnection(self.0.get_client())
+        MCaptchaRedisConnection(self.0.iter().map(|node| node.get_client()).collect())
+    }
+
+    /// Verify every node is reachable and has the module loaded, retrying
+    /// with a bounded exponential backoff instead of propagating the
+    /// first transient error. The probe runs over the connections opened
+    /// in [MCaptchaRedis::new]; no new connection is opened, so a node
+    /// whose connection has been dropped keeps failing every attempt
+    pub async fn reconnect(&self) -> CaptchaResult<()> {
+        const MAX_ATTEMPTS: u32 = 5;
+        let mut backoff = Duration::from_millis(100);
+        let max_backoff = Duration::from_secs(10);
+
+        let mut attempt = 0;
+        loop {
+            match self.get_client().is_module_loaded().await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= MAX_ATTEMPTS => return Err(e),
+                Err(_) => {
+                    attempt += 1;
+                    actix_rt::time::sleep(backoff).await;
+                    backoff = std::cmp::min(backoff * 2, max_backoff);
+                }
+            }
+        }
     }
 }
 
 impl MCaptchaRedisConnection {
+    /// Pick the connection that owns `key`, by hashing the captcha key
+    /// and routing to `hash % pool size`, the same node on every call for
+    /// a given key and pool size
+    fn shard(&self, key: &str) -> &RedisConnection {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let idx = hasher.finish() as usize % self.0.len();
+        &self.0[idx]
+    }
+
+    /// Deserialize a module reply, turning an unexpected payload into a
+    /// `CaptchaError` instead of panicking the process on a transient or
+    /// malformed response
+    fn deserialize<T: serde::de::DeserializeOwned>(res: &str) -> CaptchaResult<T> {
+        serde_json::from_str(res).map_err(|e| {
+            log::error!("mCaptcha redis module returned malformed payload: {}", e);
+            CaptchaError::MCaptchaRedisModuleError
+        })
+    }
+
     async fn is_module_loaded(&self) -> CaptchaResult<()> {
-        let modules: Vec<Vec<String>> = self
-            .0
-            .exec(redis::cmd("MODULE").arg(&["LIST"]))
-            .await
-            .unwrap();
+        let commands = vec![
+            ADD_VISITOR,
+            ADD_CAPTCHA,
+            DEL,
+            CAPTCHA_EXISTS,
+            GET,
+            ADD_CHALLENGE,
+            GET_CHALLENGE,
+            DELETE_CHALLENGE,
+            GET_DIFFICULTY,
+        ];
 
-        for list in modules.iter() {
-            match list.iter().find(|module| module.as_str() == MODULE_NAME) {
-                Some(_) => (),
-                None => return Err(CaptchaError::MCaptchaRedisModuleIsNotLoaded),
-            }
-        }
+        for node in self.0.iter() {
+            let modules: Vec<Vec<String>> =
+                node.exec(redis::cmd("MODULE").arg(&["LIST"])).await?;
 
-        let commands = vec![ADD_VISITOR, ADD_CAPTCHA, DEL, CAPTCHA_EXISTS, GET];
-
-        for cmd in commands.iter() {
-            match self
-                .0
-                .exec(redis::cmd("COMMAND").arg(&["INFO", cmd]))
-                .await
-                .unwrap()
-            {
-                Value::Bulk(mut val) => {
-                    match val.pop() {
-                        Some(Value::Nil) => {
-                            return Err(CaptchaError::MCaptchaRediSModuleCommandNotFound(
-                                cmd.to_string(),
-                            ))
-                        }
-                        _ => (),
-                    };
+            for list in modules.iter() {
+                match list.iter().find(|module| module.as_str() == MODULE_NAME) {
+                    Some(_) => (),
+                    None => return Err(CaptchaError::MCaptchaRedisModuleIsNotLoaded),
                 }
+            }
+
+            for cmd in commands.iter() {
+                match node.exec(redis::cmd("COMMAND").arg(&["INFO", cmd])).await? {
+                    Value::Bulk(mut val) => {
+                        match val.pop() {
+                            Some(Value::Nil) => {
+                                return Err(CaptchaError::MCaptchaRediSModuleCommandNotFound(
+                                    cmd.to_string(),
+                                ))
+                            }
+                            _ => (),
+                        };
+                    }
 
-                _ => (),
-            };
+                    _ => (),
+                };
+            }
         }
 
         Ok(())
@@ -103,17 +212,67 @@ impl MCaptchaRedisConnection {
 
     /// Add visitor
     pub async fn add_visitor(&self, msg: AddVisitor) -> CaptchaResult<Option<AddVisitorResult>> {
-        let res: String = self.0.exec(redis::cmd(ADD_VISITOR).arg(&[msg.0])).await?;
-        let res: AddVisitorResult = serde_json::from_str(&res).unwrap();
+        let res: String = self
+            .shard(&msg.0)
+            .exec(redis::cmd(ADD_VISITOR).arg(&[msg.0]))
+            .await?;
+        let res: AddVisitorResult = Self::deserialize(&res)?;
         Ok(Some(res))
     }
 
+    /// Add a batch of visitor hits, grouped by shard and each group sent
+    /// as a single Redis pipeline, instead of one `ADD_VISITOR`
+    /// round-trip per hit. The module recomputes difficulty on every
+    /// increment, and a pipeline preserves the order commands were
+    /// queued in, so increment ordering per captcha is unaffected.
+    /// Results are reassembled into the same order `msgs` was submitted
+    /// in, regardless of which shard each hit landed on, so a caller can
+    /// zip its own per-message state (e.g. a response channel) against
+    /// the returned `Vec` positionally
+    pub async fn add_visitors(
+        &self,
+        msgs: Vec<AddVisitor>,
+    ) -> CaptchaResult<Vec<AddVisitorResult>> {
+        let total = msgs.len();
+        let mut by_shard: Vec<Vec<(usize, AddVisitor)>> = vec![Vec::new(); self.0.len()];
+        for (original_idx, msg) in msgs.into_iter().enumerate() {
+            let mut hasher = DefaultHasher::new();
+            msg.0.hash(&mut hasher);
+            let idx = hasher.finish() as usize % self.0.len();
+            by_shard[idx].push((original_idx, msg));
+        }
+
+        let mut results: Vec<Option<AddVisitorResult>> = Vec::with_capacity(total);
+        results.resize_with(total, || None);
+        for (idx, tagged) in by_shard.into_iter().enumerate() {
+            if tagged.is_empty() {
+                continue;
+            }
+
+            let mut pipe = redis::pipe();
+            for (_, msg) in tagged.iter() {
+                pipe.cmd(ADD_VISITOR).arg(&[msg.0.clone()]);
+            }
+
+            let res: Vec<String> = self.0[idx].exec_pipe(&pipe).await?;
+            for ((original_idx, _), entry) in tagged.into_iter().zip(res.into_iter()) {
+                let entry: AddVisitorResult = Self::deserialize(&entry)?;
+                results[original_idx] = Some(entry);
+            }
+        }
+
+        Ok(results
+            .into_iter()
+            .map(|r| r.expect("every submitted visitor hit is assigned to exactly one shard"))
+            .collect())
+    }
+
     /// Register new mCaptcha with Redis
     pub async fn add_mcaptcha(&self, msg: AddSite) -> CaptchaResult<()> {
         let name = msg.id;
         let captcha: CreateMCaptcha = msg.mcaptcha.into();
         let payload = serde_json::to_string(&captcha).unwrap();
-        self.0
+        self.shard(&name)
             .exec(redis::cmd(ADD_CAPTCHA).arg(&[name, payload]))
             .await?;
         Ok(())
@@ -122,7 +281,7 @@ impl MCaptchaRedisConnection {
     /// Check if an mCaptcha object is available in Redis
     pub async fn check_captcha_exists(&self, captcha: &str) -> CaptchaResult<bool> {
         let exists: usize = self
-            .0
+            .shard(captcha)
             .exec(redis::cmd(CAPTCHA_EXISTS).arg(&[captcha]))
             .await?;
         if exists == 1 {
@@ -141,15 +300,157 @@ impl MCaptchaRedisConnection {
 
     /// Delete an mCaptcha object from Redis
     pub async fn delete_captcha(&self, captcha: &str) -> CaptchaResult<()> {
-        self.0.exec(redis::cmd(DEL).arg(&[captcha])).await?;
+        self.shard(captcha)
+            .exec(redis::cmd(DEL).arg(&[captcha]))
+            .await?;
         Ok(())
     }
 
     /// Get number of visitors of an mCaptcha object from Redis
     pub async fn get_visitors(&self, captcha: &str) -> CaptchaResult<usize> {
-        let visitors: usize = self.0.exec(redis::cmd(GET).arg(&[captcha])).await?;
+        let visitors: usize = self
+            .shard(captcha)
+            .exec(redis::cmd(GET).arg(&[captcha]))
+            .await?;
         Ok(visitors)
     }
+
+    /// Get the module-computed current difficulty factor and active
+    /// defense level of an mCaptcha object from Redis
+    pub async fn get_difficulty(&self, captcha: &str) -> CaptchaResult<Difficulty> {
+        let res: String = self
+            .shard(captcha)
+            .exec(redis::cmd(GET_DIFFICULTY).arg(&[captcha]))
+            .await?;
+        let difficulty: Difficulty = Self::deserialize(&res)?;
+        Ok(difficulty)
+    }
+
+    /// Persist a PoW challenge issued for `captcha` so that it can be
+    /// verified from any node sharing this Redis instance, with a
+    /// server-side TTL equal to `msg.duration`
+    pub async fn add_challenge(&self, captcha: &str, msg: AddChallenge) -> CaptchaResult<()> {
+        let duration = msg.duration.to_string();
+        let payload = serde_json::to_string(&msg).unwrap();
+        self.shard(captcha)
+            .exec(redis::cmd(ADD_CHALLENGE).arg(&[captcha, &payload, &duration]))
+            .await?;
+        Ok(())
+    }
+
+    /// Fetch the PoW challenge previously issued for `captcha`, if it
+    /// hasn't expired
+    pub async fn get_challenge(&self, captcha: &str) -> CaptchaResult<Option<AddChallenge>> {
+        let res: Option<String> = self
+            .shard(captcha)
+            .exec(redis::cmd(GET_CHALLENGE).arg(&[captcha]))
+            .await?;
+
+        match res {
+            Some(res) => {
+                let challenge: AddChallenge = Self::deserialize(&res)?;
+                Ok(Some(challenge))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Delete the PoW challenge stored for `captcha`, e.g. once it has
+    /// been verified
+    pub async fn delete_challenge(&self, captcha: &str) -> CaptchaResult<()> {
+        self.shard(captcha)
+            .exec(redis::cmd(DELETE_CHALLENGE).arg(&[captcha]))
+            .await?;
+        Ok(())
+    }
+}
+
+/// A buffered [AddVisitor] hit together with the channel [VisitorBuffer]
+/// uses to report the outcome of the window it's flushed in back to the
+/// caller, so a Redis hiccup during a flush doesn't get lost as a log
+/// line nobody's watching
+pub struct BufferedVisitor {
+    pub visitor: AddVisitor,
+    pub result: oneshot::Sender<CaptchaResult<AddVisitorResult>>,
+}
+
+impl Message for BufferedVisitor {
+    type Result = ();
+}
+
+/// Actor that accumulates [AddVisitor] hits for `flush_interval` and
+/// flushes them to Redis as a single pipelined batch via
+/// [MCaptchaRedisConnection::add_visitors], so that a traffic spike costs
+/// one round-trip per shard per window instead of one per request. On a
+/// flush error [MCaptchaRedis::reconnect] is run, which re-probes the
+/// existing connections with backoff but opens no new connection, and
+/// every hit of that window is answered with an error
+pub struct VisitorBuffer {
+    redis: Arc<MCaptchaRedis>,
+    flush_interval: Duration,
+    buffer: Vec<BufferedVisitor>,
+}
+
+impl VisitorBuffer {
+    /// Get a new [VisitorBuffer], flushing every `flush_interval`
+    pub fn new(redis: Arc<MCaptchaRedis>, flush_interval: Duration) -> Self {
+        VisitorBuffer {
+            redis,
+            flush_interval,
+            buffer: Vec::new(),
+        }
+    }
+
+    async fn flush(redis: &MCaptchaRedis, buffered: Vec<BufferedVisitor>) {
+        if buffered.is_empty() {
+            return;
+        }
+
+        let (visitors, senders): (Vec<_>, Vec<_>) =
+            buffered.into_iter().map(|b| (b.visitor, b.result)).unzip();
+
+        match redis.get_client().add_visitors(visitors).await {
+            Ok(results) => {
+                for (sender, result) in senders.into_iter().zip(results.into_iter()) {
+                    let _ = sender.send(Ok(result));
+                }
+            }
+            Err(e) => {
+                log::error!(
+                    "error flushing buffered visitors to Redis, reconnecting: {}",
+                    e
+                );
+                if let Err(e) = redis.reconnect().await {
+                    log::error!("reconnect failed: {}", e);
+                }
+                for sender in senders.into_iter() {
+                    let _ = sender.send(Err(CaptchaError::MCaptchaRedisModuleError));
+                }
+            }
+        }
+    }
+}
+
+impl Actor for VisitorBuffer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.flush_interval, |act, ctx| {
+            let redis = act.redis.clone();
+            let buffer = std::mem::take(&mut act.buffer);
+            ctx.spawn(actix::fut::wrap_future(async move {
+                VisitorBuffer::flush(&redis, buffer).await
+            }));
+        });
+    }
+}
+
+impl Handler<BufferedVisitor> for VisitorBuffer {
+    type Result = ();
+
+    fn handle(&mut self, msg: BufferedVisitor, _ctx: &mut Self::Context) {
+        self.buffer.push(msg);
+    }
 }
 
 #[cfg(test)]
@@ -167,7 +468,7 @@ pub mod tests {
             .await
             .unwrap();
 
-        let r = MCaptchaRedis(redis);
+        let r = MCaptchaRedis(vec![redis]);
         let r = r.get_client();
         {
             let _ = r.delete_captcha(CAPTCHA_NAME).await;
@@ -192,6 +493,123 @@ pub mod tests {
         let visitors = r.get_visitors(CAPTCHA_NAME).await.unwrap();
         assert_eq!(visitors, 2);
 
+        let batch = vec![
+            AddVisitor(CAPTCHA_NAME.into()),
+            AddVisitor(CAPTCHA_NAME.into()),
+        ];
+        let results = r.add_visitors(batch).await.unwrap();
+        assert_eq!(results.len(), 2);
+        let visitors = r.get_visitors(CAPTCHA_NAME).await.unwrap();
+        assert_eq!(visitors, 4);
+
+        let difficulty = r.get_difficulty(CAPTCHA_NAME).await.unwrap();
+        assert!(difficulty.difficulty > 0);
+
+        let add_challenge_msg = AddChallenge {
+            difficulty: 100,
+            duration: 30,
+            challenge: "foo".into(),
+        };
+        assert!(r
+            .add_challenge(CAPTCHA_NAME, add_challenge_msg.clone())
+            .await
+            .is_ok());
+        let challenge = r.get_challenge(CAPTCHA_NAME).await.unwrap();
+        assert_eq!(challenge, Some(add_challenge_msg));
+
+        assert!(r.delete_challenge(CAPTCHA_NAME).await.is_ok());
+        assert_eq!(r.get_challenge(CAPTCHA_NAME).await.unwrap(), None);
+
         assert!(r.delete_captcha(CAPTCHA_NAME).await.is_ok());
     }
+
+    #[actix_rt::test]
+    async fn sharding_routes_keys_to_the_node_the_formula_picks() {
+        let node_a = Redis::new(RedisConfig::Single(REDIS_URL.into()))
+            .await
+            .unwrap();
+        let node_b = Redis::new(RedisConfig::Single(REDIS_URL.into()))
+            .await
+            .unwrap();
+
+        let r = MCaptchaRedis(vec![node_a, node_b]);
+        let r = r.get_client();
+
+        for key in [
+            "REDIS_SHARD_TEST_A",
+            "REDIS_SHARD_TEST_B",
+            "REDIS_SHARD_TEST_C",
+        ] {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            let expected_idx = hasher.finish() as usize % r.0.len();
+            assert_eq!(
+                r.shard(key) as *const RedisConnection,
+                &r.0[expected_idx] as *const RedisConnection
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn new_rejects_empty_shard_node_list() {
+        assert!(MCaptchaRedis::new(RedisConfig::Sharded(vec![]))
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn add_visitors_preserves_order_across_shards() {
+        const CAPTCHA_A: &str = "REDIS_SHARD_ORDER_TEST_A";
+        const CAPTCHA_B: &str = "REDIS_SHARD_ORDER_TEST_C";
+
+        let node_a = Redis::new(RedisConfig::Single(REDIS_URL.into()))
+            .await
+            .unwrap();
+        let node_b = Redis::new(RedisConfig::Single(REDIS_URL.into()))
+            .await
+            .unwrap();
+
+        let r = MCaptchaRedis(vec![node_a, node_b]);
+        let r = r.get_client();
+
+        // the fix being tested only matters if the two captchas actually
+        // land in different by_shard buckets; if the hash formula ever
+        // changes and collides them, this test would stop exercising the
+        // bug it's guarding against, so assert the premise up front
+        let mut hasher = DefaultHasher::new();
+        CAPTCHA_A.hash(&mut hasher);
+        let idx_a = hasher.finish() as usize % r.0.len();
+        let mut hasher = DefaultHasher::new();
+        CAPTCHA_B.hash(&mut hasher);
+        let idx_b = hasher.finish() as usize % r.0.len();
+        assert_ne!(idx_a, idx_b, "test captchas must land on different shards");
+
+        for captcha in [CAPTCHA_A, CAPTCHA_B] {
+            let _ = r.delete_captcha(captcha).await;
+            r.add_mcaptcha(AddSite {
+                id: captcha.into(),
+                mcaptcha: get_mcaptcha(),
+            })
+            .await
+            .unwrap();
+        }
+
+        // give the two captchas divergent visitor counts beforehand so a
+        // swapped result is overwhelmingly likely to be caught below
+        for _ in 0..50 {
+            r.add_visitor(AddVisitor(CAPTCHA_A.into())).await.unwrap();
+        }
+
+        let batch = vec![AddVisitor(CAPTCHA_A.into()), AddVisitor(CAPTCHA_B.into())];
+        let results = r.add_visitors(batch).await.unwrap();
+        assert_eq!(results.len(), 2);
+
+        let difficulty_a = r.get_difficulty(CAPTCHA_A).await.unwrap();
+        let difficulty_b = r.get_difficulty(CAPTCHA_B).await.unwrap();
+        assert_eq!(results[0].difficulty, difficulty_a.difficulty);
+        assert_eq!(results[1].difficulty, difficulty_b.difficulty);
+
+        r.delete_captcha(CAPTCHA_A).await.unwrap();
+        r.delete_captcha(CAPTCHA_B).await.unwrap();
+    }
 }
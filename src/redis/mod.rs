@@ -0,0 +1,90 @@
+/*
+ * mCaptcha - A proof of work based DoS protection system
+ * Copyright © 2021 Aravinth Manivannan <realravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+//! Thin wrapper over a single Redis node's connection
+
+use redis::aio::MultiplexedConnection;
+use redis::{Client, Cmd, FromRedisValue, Pipeline};
+
+use crate::errors::*;
+
+/// Redis endpoint(s) to connect to. [RedisConfig::Sharded] is a list of
+/// independently-configured nodes that mCaptcha shards captcha keys
+/// across client-side (hash of the key mod node count); it is not Redis
+/// Cluster protocol support, so there's no slot map, `MOVED`/`ASK`
+/// handling, or topology refresh on node addition/removal
+#[derive(Debug, Clone)]
+pub enum RedisConfig {
+    Single(String),
+    Sharded(Vec<String>),
+}
+
+/// A single Redis node
+pub struct Redis {
+    con: MultiplexedConnection,
+}
+
+impl Redis {
+    /// Connect to a single Redis node. [RedisConfig::Sharded] is resolved
+    /// by the caller into one [Redis] per node
+    pub async fn new(redis: RedisConfig) -> CaptchaResult<Self> {
+        let url = match redis {
+            RedisConfig::Single(url) => url,
+            RedisConfig::Sharded(mut urls) if !urls.is_empty() => urls.remove(0),
+            RedisConfig::Sharded(_) => return Err(CaptchaError::MCaptchaRedisModuleError),
+        };
+
+        let client = Client::open(url).map_err(CaptchaError::RedisError)?;
+        let con = client
+            .get_multiplexed_tokio_connection()
+            .await
+            .map_err(CaptchaError::RedisError)?;
+        Ok(Redis { con })
+    }
+
+    /// Get a cheap-to-clone handle to this node's connection
+    pub fn get_client(&self) -> RedisConnection {
+        RedisConnection(self.con.clone())
+    }
+}
+
+/// Handle to a Redis node's multiplexed connection
+///
+/// Each command runs on its own clone of the connection, so a shared
+/// handle can be used from several tasks at once
+#[derive(Clone)]
+pub struct RedisConnection(MultiplexedConnection);
+
+impl RedisConnection {
+    /// Run a single command against this node
+    pub async fn exec<T: FromRedisValue>(&self, cmd: &mut Cmd) -> CaptchaResult<T> {
+        let mut con = self.0.clone();
+        let res = cmd.query_async(&mut con).await.map_err(CaptchaError::RedisError)?;
+        Ok(res)
+    }
+
+    /// Run a pipeline of commands against this node in a single
+    /// round-trip
+    pub async fn exec_pipe<T: FromRedisValue>(&self, pipe: &Pipeline) -> CaptchaResult<T> {
+        let mut con = self.0.clone();
+        let res = pipe
+            .query_async(&mut con)
+            .await
+            .map_err(CaptchaError::RedisError)?;
+        Ok(res)
+    }
+}